@@ -17,16 +17,40 @@
 //! Encoded text for ads
 
 use std::error::Error;
+use std::fmt;
 use std::io::{self, Write, Read, Cursor};
+use std::convert::TryFrom;
 use byteorder::{LittleEndian, WriteBytesExt, ReadBytesExt};
 
 /// A text object that stores a string in space saving encoding
 /// currently UTF-8 or UTF-16 with or without snappy compression
+///
+/// `encoded` is never empty and its flag byte never sets a bit outside
+/// `KNOWN_FLAGS`: `new` and `new_normalized` build the flag byte themselves,
+/// and every other way in, including deserialization (see the
+/// `TryFrom<Vec<u8>>` impl below, which `#[serde(try_from = "Vec<u8>")]`
+/// routes every `Deserialize` through), goes through `from_encoded`'s
+/// validation. Methods below that index `self.encoded[0]` rely on this.
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq, Clone)]
+#[serde(into = "Vec<u8>", try_from = "Vec<u8>")]
 pub struct Text {
     encoded: Vec<u8>
 }
 
+impl From<Text> for Vec<u8> {
+    fn from(text: Text) -> Vec<u8> {
+        text.encoded
+    }
+}
+
+impl TryFrom<Vec<u8>> for Text {
+    type Error = TextError;
+
+    fn try_from(encoded: Vec<u8>) -> Result<Text, TextError> {
+        Text::from_encoded(&encoded)
+    }
+}
+
 // default encoding is UTF-8 uncompressed
 // Below are *bits* of the encoding byte.
 // There are thus 4 options:
@@ -38,6 +62,15 @@ pub struct Text {
 const UTF_16:u8 = 1; // bit 0
 // uses compressed encoding
 const COMPRESSED:u8 = 2; // bit 1
+// the decoded text was normalized by `new_normalized` (NFC over the
+// decompositions this module knows, see CANONICAL_DECOMPOSITIONS); this does not change
+// how the bytes are encoded, it only records that the producer already
+// normalized them, so consumers such as the `iblt`/`content` set-reconciliation
+// can compare and hash texts without normalizing again
+const NORMALIZED:u8 = 4; // bit 2
+// flag bits this version of the code understands; anything else in the
+// flag byte means the `Text` came from a newer or incompatible writer
+const KNOWN_FLAGS: u8 = UTF_16 | COMPRESSED | NORMALIZED;
 
 /**
  * The reason why there are so many encoding options is to allow
@@ -65,10 +98,531 @@ const COMPRESSED:u8 = 2; // bit 1
  * languages.
  */
 
+/// Error returned while decoding a [`Text`] that turns out to be malformed.
+///
+/// `Text` is frequently constructed from bytes received over `p2p_defiads`,
+/// so decoding must never panic on attacker-controlled input; this type is
+/// how decode failures are reported instead.
+#[derive(Debug)]
+pub enum TextError {
+    /// `from_encoded` was given a zero-length buffer
+    Empty,
+    /// `from_encoded` was given a flag byte using bits this version does not understand
+    UnknownFlags(u8),
+    /// the encoded bytes do not decode to a valid string in the claimed
+    /// encoding; `position` is the offset, within the decompressed payload,
+    /// of the first invalid byte (UTF-8) or code unit (UTF-16)
+    Invalid { position: usize },
+}
+
+impl fmt::Display for TextError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TextError::Empty => write!(f, "empty encoded text"),
+            TextError::UnknownFlags(flags) => write!(f, "unknown text encoding flags: {:#04x}", flags),
+            TextError::Invalid { position } => write!(f, "invalid text encoding at position {}", position),
+        }
+    }
+}
+
+impl Error for TextError {}
+
+// classification of a UTF-8 lead byte, used to drive the decode state
+// machine in decode_utf8_scalar below
+#[derive(Clone, Copy, PartialEq)]
+enum Utf8Class {
+    Ascii,
+    Cont,
+    Lead2,
+    Lead3Low,  // 0xE0: second byte restricted to 0xA0..=0xBF, rejects overlong forms
+    Lead3Mid,  // 0xE1..=0xEC, 0xEE..=0xEF
+    Lead3High, // 0xED: second byte restricted to 0x80..=0x9F, rejects UTF-16 surrogates
+    Lead4Low,  // 0xF0: second byte restricted to 0x90..=0xBF, rejects overlong forms
+    Lead4Mid,  // 0xF1..=0xF3
+    Lead4High, // 0xF4: second byte restricted to 0x80..=0x8F, rejects scalars > U+10FFFF
+    Invalid,
+}
+
+fn classify(byte: u8) -> Utf8Class {
+    match byte {
+        0x00..=0x7F => Utf8Class::Ascii,
+        0x80..=0xBF => Utf8Class::Cont,
+        0xC2..=0xDF => Utf8Class::Lead2,
+        0xE0 => Utf8Class::Lead3Low,
+        0xE1..=0xEC | 0xEE..=0xEF => Utf8Class::Lead3Mid,
+        0xED => Utf8Class::Lead3High,
+        0xF0 => Utf8Class::Lead4Low,
+        0xF1..=0xF3 => Utf8Class::Lead4Mid,
+        0xF4 => Utf8Class::Lead4High,
+        // 0xC0, 0xC1 (always overlong) and 0xF5..=0xFF (> U+10FFFF)
+        _ => Utf8Class::Invalid,
+    }
+}
+
+/// decode a single scalar starting at `data[0]`.
+/// on success returns the char and the number of bytes it consumed.
+/// on failure returns the offset, from `data[0]`, of the byte that broke
+/// the sequence: 0 if `data[0]` itself is not a valid lead byte, or the
+/// number of bytes of valid prefix already consumed otherwise.
+fn decode_utf8_scalar(data: &[u8]) -> Result<(char, usize), usize> {
+    let lead = data[0];
+    let (need, lead_lo, lead_hi, mut code_point) = match classify(lead) {
+        Utf8Class::Ascii => return Ok((lead as char, 1)),
+        Utf8Class::Cont | Utf8Class::Invalid => return Err(0),
+        Utf8Class::Lead2 => (1, 0x80, 0xBF, (lead & 0x1F) as u32),
+        Utf8Class::Lead3Low => (2, 0xA0, 0xBF, (lead & 0x0F) as u32),
+        Utf8Class::Lead3Mid => (2, 0x80, 0xBF, (lead & 0x0F) as u32),
+        Utf8Class::Lead3High => (2, 0x80, 0x9F, (lead & 0x0F) as u32),
+        Utf8Class::Lead4Low => (3, 0x90, 0xBF, (lead & 0x07) as u32),
+        Utf8Class::Lead4Mid => (3, 0x80, 0xBF, (lead & 0x07) as u32),
+        Utf8Class::Lead4High => (3, 0x80, 0x8F, (lead & 0x07) as u32),
+    };
+    let mut consumed = 1;
+    for i in 0..need {
+        if consumed >= data.len() {
+            return Err(consumed);
+        }
+        let byte = data[consumed];
+        let (lo, hi) = if i == 0 { (lead_lo, lead_hi) } else { (0x80, 0xBF) };
+        if byte < lo || byte > hi {
+            return Err(consumed);
+        }
+        code_point = (code_point << 6) | (byte & 0x3F) as u32;
+        consumed += 1;
+    }
+    match char::from_u32(code_point) {
+        Some(c) => Ok((c, consumed)),
+        None => Err(consumed),
+    }
+}
+
+/// strict UTF-8 decode, returning the byte offset of the first invalid
+/// byte on failure
+fn decode_utf8_strict(data: &[u8]) -> Result<String, usize> {
+    let mut s = String::with_capacity(data.len());
+    let mut pos = 0;
+    while pos < data.len() {
+        match decode_utf8_scalar(&data[pos..]) {
+            Ok((c, len)) => { s.push(c); pos += len; }
+            Err(offset) => return Err(pos + offset),
+        }
+    }
+    Ok(s)
+}
+
+/// lossy UTF-8 decode: every invalid subsequence is replaced by a single
+/// U+FFFD and decoding resumes right after it, so malformed or truncated
+/// input never produces an error
+fn decode_utf8_lossy(data: &[u8]) -> (String, bool) {
+    let mut s = String::with_capacity(data.len());
+    let mut pos = 0;
+    let mut was_lossy = false;
+    while pos < data.len() {
+        match decode_utf8_scalar(&data[pos..]) {
+            Ok((c, len)) => { s.push(c); pos += len; }
+            Err(offset) => { s.push('\u{FFFD}'); was_lossy = true; pos += offset.max(1); }
+        }
+    }
+    (s, was_lossy)
+}
+
+/// strict UTF-16 decode, returning the code unit offset of the first
+/// invalid (unpaired) surrogate on failure. Written by hand rather than
+/// via `char::decode_utf16` so the error position can be tracked in code
+/// units even when a valid surrogate pair precedes it.
+fn decode_utf16_strict(units: &[u16]) -> Result<String, usize> {
+    let mut s = String::with_capacity(units.len());
+    let mut pos = 0;
+    while pos < units.len() {
+        let unit = units[pos];
+        match unit {
+            0xD800..=0xDBFF => match units.get(pos + 1) {
+                Some(&low) if (0xDC00..=0xDFFF).contains(&low) => {
+                    let c = 0x10000 + (((unit as u32 - 0xD800) << 10) | (low as u32 - 0xDC00));
+                    s.push(char::from_u32(c).expect("valid surrogate pair decodes to a scalar value"));
+                    pos += 2;
+                }
+                _ => return Err(pos),
+            },
+            0xDC00..=0xDFFF => return Err(pos),
+            _ => {
+                s.push(char::from_u32(unit as u32).expect("non-surrogate code unit is a scalar value"));
+                pos += 1;
+            }
+        }
+    }
+    Ok(s)
+}
+
+// --- NFC normalization -----------------------------------------------------
+//
+// Canonical_Combining_Class for the Combining Diacritical Marks block
+// (U+0300-U+036F), which is what the accented letters in
+// CANONICAL_DECOMPOSITIONS below decompose into. Characters not listed here
+// are treated as starters (ccc 0), including combining marks of other
+// blocks that have a nonzero class in Unicode (e.g. the Devanagari virama
+// U+094D or the Arabic harakat U+064B-U+0652): those are neither reordered
+// nor kept attached to their base by cluster_boundaries.
+fn combining_class(c: char) -> u8 {
+    match c as u32 {
+        0x0300..=0x0314 => 230,
+        0x0315 => 232,
+        0x0316..=0x0319 => 220,
+        0x031A => 232,
+        0x031B => 216,
+        0x031C..=0x0320 => 220,
+        0x0321..=0x0322 => 202,
+        0x0323..=0x0326 => 220,
+        0x0327..=0x0328 => 202,
+        0x0329..=0x0333 => 220,
+        0x0334..=0x0338 => 1,
+        0x0339..=0x033C => 220,
+        0x033D..=0x0344 => 230,
+        0x0345 => 240,
+        0x0346 => 230,
+        0x0347..=0x0349 => 220,
+        0x034A..=0x034C => 230,
+        0x034D..=0x034E => 234,
+        0x0350..=0x0352 => 230,
+        0x0353..=0x0356 => 220,
+        0x0357 => 230,
+        0x0358 => 232,
+        0x0359..=0x035A => 220,
+        0x035B => 230,
+        0x035D..=0x035E => 234,
+        0x0363..=0x036F => 230,
+        _ => 0,
+    }
+}
+
+// Canonical decompositions for the precomposed letters that make up ad
+// text in the languages this has been checked against so far: (composed,
+// base, combining mark). This is a practical subset rather than the full
+// Unicode Character Database (pulling in or generating the full UCD
+// decomposition table is out of scope without a data-generation build
+// step). Coverage is currently Western European Latin, a few Czech caron
+// letters, the handful of precomposed Cyrillic letters common in Russian
+// (й/ё and their uppercase forms), and the Greek tonos vowels. Scripts not
+// listed here either have no canonical decomposition (Armenian, Georgian)
+// or simply aren't covered yet (Japanese kana with (semi-)voiced marks,
+// Arabic hamza/madda letters, Devanagari nukta letters, Hebrew presentation
+// forms, CJK compatibility ideographs, other Cyrillic-using languages'
+// precomposed letters, Greek ΐ/ΰ, Vietnamese, etc.) — text in
+// an uncovered script round-trips, but two authors whose precomposed vs.
+// decomposed spellings of the *same* uncovered letter won't dedupe or
+// hash equal until it's added below.
+const CANONICAL_DECOMPOSITIONS: &[(char, char, char)] = &[
+    ('À', 'A', '\u{300}'), ('Á', 'A', '\u{301}'), ('Â', 'A', '\u{302}'), ('Ã', 'A', '\u{303}'),
+    ('Ä', 'A', '\u{308}'), ('Å', 'A', '\u{30A}'), ('Ç', 'C', '\u{327}'),
+    ('È', 'E', '\u{300}'), ('É', 'E', '\u{301}'), ('Ê', 'E', '\u{302}'), ('Ë', 'E', '\u{308}'),
+    ('Ì', 'I', '\u{300}'), ('Í', 'I', '\u{301}'), ('Î', 'I', '\u{302}'), ('Ï', 'I', '\u{308}'),
+    ('Ñ', 'N', '\u{303}'),
+    ('Ò', 'O', '\u{300}'), ('Ó', 'O', '\u{301}'), ('Ô', 'O', '\u{302}'), ('Õ', 'O', '\u{303}'), ('Ö', 'O', '\u{308}'),
+    ('Ù', 'U', '\u{300}'), ('Ú', 'U', '\u{301}'), ('Û', 'U', '\u{302}'), ('Ü', 'U', '\u{308}'),
+    ('Ý', 'Y', '\u{301}'),
+    ('à', 'a', '\u{300}'), ('á', 'a', '\u{301}'), ('â', 'a', '\u{302}'), ('ã', 'a', '\u{303}'),
+    ('ä', 'a', '\u{308}'), ('å', 'a', '\u{30A}'), ('ç', 'c', '\u{327}'),
+    ('è', 'e', '\u{300}'), ('é', 'e', '\u{301}'), ('ê', 'e', '\u{302}'), ('ë', 'e', '\u{308}'),
+    ('ì', 'i', '\u{300}'), ('í', 'i', '\u{301}'), ('î', 'i', '\u{302}'), ('ï', 'i', '\u{308}'),
+    ('ñ', 'n', '\u{303}'),
+    ('ò', 'o', '\u{300}'), ('ó', 'o', '\u{301}'), ('ô', 'o', '\u{302}'), ('õ', 'o', '\u{303}'), ('ö', 'o', '\u{308}'),
+    ('ù', 'u', '\u{300}'), ('ú', 'u', '\u{301}'), ('û', 'u', '\u{302}'), ('ü', 'u', '\u{308}'),
+    ('ý', 'y', '\u{301}'), ('ÿ', 'y', '\u{308}'),
+    ('Č', 'C', '\u{30C}'), ('č', 'c', '\u{30C}'), ('Š', 'S', '\u{30C}'), ('š', 's', '\u{30C}'),
+    ('Ž', 'Z', '\u{30C}'), ('ž', 'z', '\u{30C}'), ('Ě', 'E', '\u{30C}'), ('ě', 'e', '\u{30C}'),
+    // Cyrillic: short I and "yo", the two precomposed Russian letters an
+    // ad is realistically going to contain
+    ('Й', 'И', '\u{306}'), ('й', 'и', '\u{306}'),
+    ('Ё', 'Е', '\u{308}'), ('ё', 'е', '\u{308}'),
+    // Greek: the monotonic tonos vowels
+    ('Ά', 'Α', '\u{301}'), ('ά', 'α', '\u{301}'),
+    ('Έ', 'Ε', '\u{301}'), ('έ', 'ε', '\u{301}'),
+    ('Ή', 'Η', '\u{301}'), ('ή', 'η', '\u{301}'),
+    ('Ί', 'Ι', '\u{301}'), ('ί', 'ι', '\u{301}'),
+    ('Ό', 'Ο', '\u{301}'), ('ό', 'ο', '\u{301}'),
+    ('Ύ', 'Υ', '\u{301}'), ('ύ', 'υ', '\u{301}'),
+    ('Ώ', 'Ω', '\u{301}'), ('ώ', 'ω', '\u{301}'),
+];
+
+// Hangul syllables decompose algorithmically rather than by table lookup
+// (UAX #15); these constants follow the standard formula.
+const S_BASE: u32 = 0xAC00;
+const L_BASE: u32 = 0x1100;
+const V_BASE: u32 = 0x1161;
+const T_BASE: u32 = 0x11A7;
+const L_COUNT: u32 = 19;
+const V_COUNT: u32 = 21;
+const T_COUNT: u32 = 28;
+const N_COUNT: u32 = V_COUNT * T_COUNT;
+const S_COUNT: u32 = L_COUNT * N_COUNT;
+
+fn decompose_hangul(c: char) -> Option<(char, char, Option<char>)> {
+    let s = c as u32;
+    if !(S_BASE..S_BASE + S_COUNT).contains(&s) {
+        return None;
+    }
+    let s_index = s - S_BASE;
+    let l = L_BASE + s_index / N_COUNT;
+    let v = V_BASE + (s_index % N_COUNT) / T_COUNT;
+    let t_index = s_index % T_COUNT;
+    let t = if t_index > 0 { Some(char::from_u32(T_BASE + t_index).unwrap()) } else { None };
+    Some((char::from_u32(l).unwrap(), char::from_u32(v).unwrap(), t))
+}
+
+fn compose_hangul(a: char, b: char) -> Option<char> {
+    let (a, b) = (a as u32, b as u32);
+    if (L_BASE..L_BASE + L_COUNT).contains(&a) && (V_BASE..V_BASE + V_COUNT).contains(&b) {
+        let l_index = a - L_BASE;
+        let v_index = b - V_BASE;
+        let s_index = l_index * N_COUNT + v_index * T_COUNT;
+        return char::from_u32(S_BASE + s_index);
+    }
+    if (S_BASE..S_BASE + S_COUNT).contains(&a) && (a - S_BASE).is_multiple_of(T_COUNT)
+        && (T_BASE + 1..T_BASE + T_COUNT).contains(&b) {
+        return char::from_u32(a + (b - T_BASE));
+    }
+    None
+}
+
+fn canonical_decomposition(c: char) -> Option<(char, char)> {
+    CANONICAL_DECOMPOSITIONS.iter()
+        .find(|&&(composed, _, _)| composed == c)
+        .map(|&(_, base, mark)| (base, mark))
+}
+
+fn compose_pair(starter: char, mark: char) -> Option<char> {
+    if let Some(composed) = compose_hangul(starter, mark) {
+        return Some(composed);
+    }
+    CANONICAL_DECOMPOSITIONS.iter()
+        .find(|&&(_, base, m)| base == starter && m == mark)
+        .map(|&(composed, _, _)| composed)
+}
+
+fn push_canonically_decomposed(c: char, out: &mut Vec<char>) {
+    if let Some((l, v, t)) = decompose_hangul(c) {
+        out.push(l);
+        out.push(v);
+        if let Some(t) = t { out.push(t); }
+        return;
+    }
+    if let Some((base, mark)) = canonical_decomposition(c) {
+        push_canonically_decomposed(base, out);
+        push_canonically_decomposed(mark, out);
+    } else {
+        out.push(c);
+    }
+}
+
+// stably reorder every maximal run of combining marks (ccc != 0) by
+// ascending combining class, leaving starters (ccc == 0) fixed in place
+fn canonically_reorder(chars: &mut [char]) {
+    let mut i = 0;
+    while i < chars.len() {
+        if combining_class(chars[i]) == 0 {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        while i < chars.len() && combining_class(chars[i]) != 0 {
+            i += 1;
+        }
+        chars[start..i].sort_by_key(|&c| combining_class(c));
+    }
+}
+
+// walk left to right, composing each character into the last starter when
+// the canonical composition table allows it; a composition is blocked if a
+// mark with ccc >= the candidate's ccc intervened since that starter
+fn canonically_compose(chars: &[char]) -> Vec<char> {
+    let mut result: Vec<char> = Vec::with_capacity(chars.len());
+    let mut starter_index: Option<usize> = None;
+    let mut last_class: i16 = -1;
+    for &c in chars {
+        let class = combining_class(c) as i16;
+        if let Some(si) = starter_index {
+            if last_class == -1 || last_class < class {
+                if let Some(composed) = compose_pair(result[si], c) {
+                    result[si] = composed;
+                    continue;
+                }
+            }
+        }
+        result.push(c);
+        if class == 0 {
+            starter_index = Some(result.len() - 1);
+            last_class = -1;
+        } else {
+            last_class = class;
+        }
+    }
+    result
+}
+
+/// normalize a string to Unicode Normalization Form C, as far as the
+/// decompositions in CANONICAL_DECOMPOSITIONS, Hangul and the combining
+/// classes in combining_class reach; other characters are left as they are
+fn to_nfc(s: &str) -> String {
+    let mut decomposed = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        push_canonically_decomposed(c, &mut decomposed);
+    }
+    canonically_reorder(&mut decomposed);
+    canonically_compose(&decomposed).into_iter().collect()
+}
+
+// --- safe display ------------------------------------------------------
+//
+// Ad text is fully attacker-controlled, so whatever renders it to a human
+// needs a way to neutralize scalars that are only useful for visually
+// disguising the rest of the text: bidi overrides, invisible spacers, raw
+// control characters and noncharacters.
+
+// C0 and C1 control characters
+fn is_control(c: char) -> bool {
+    matches!(c as u32, 0x00..=0x1F | 0x7F..=0x9F)
+}
+
+// bidirectional formatting controls, e.g. RIGHT-TO-LEFT OVERRIDE (U+202E)
+fn is_bidi_control(c: char) -> bool {
+    matches!(c as u32, 0x200E | 0x200F | 0x202A..=0x202E | 0x2066..=0x2069)
+}
+
+// zero-width and otherwise invisible characters
+fn is_invisible(c: char) -> bool {
+    matches!(c as u32, 0x200B..=0x200D | 0x2060 | 0xFEFF)
+}
+
+// noncharacters reserved by the standard and never assigned a glyph:
+// U+FDD0..=U+FDEF, and the last two code points of every plane
+fn is_noncharacter(c: char) -> bool {
+    let cp = c as u32;
+    (0xFDD0..=0xFDEF).contains(&cp) || (cp & 0xFFFE) == 0xFFFE
+}
+
+fn needs_display_escape(c: char) -> bool {
+    is_control(c) || is_bidi_control(c) || is_invisible(c) || is_noncharacter(c)
+}
+
+// byte offsets, in ascending order and always including 0 and s.len(), that
+// a caller may safely cut at without orphaning a trailing combining mark: a
+// boundary sits right before a starter (ccc == 0), never between a base
+// character and the combining marks attached to it
+fn cluster_boundaries(s: &str) -> Vec<usize> {
+    let mut boundaries = vec![0];
+    for (offset, c) in s.char_indices() {
+        if offset != 0 && combining_class(c) == 0 {
+            boundaries.push(offset);
+        }
+    }
+    boundaries.push(s.len());
+    boundaries.dedup();
+    boundaries
+}
+
+/// error returned by [`UnicodeRangeSet::parse`] for a malformed range spec
+#[derive(Debug)]
+pub struct RangeSetParseError(String);
+
+impl fmt::Display for RangeSetParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid unicode range set: {}", self.0)
+    }
+}
+
+impl Error for RangeSetParseError {}
+
+/// A sorted, merged set of inclusive Unicode scalar value ranges, used to
+/// express a per-node content policy such as "only these scripts may
+/// appear in an ad" (e.g. `"0000-024F,0370-03FF,4E00-9FFF"`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnicodeRangeSet {
+    // sorted, non-overlapping, non-adjacent inclusive ranges
+    ranges: Vec<(u32, u32)>,
+}
+
+impl UnicodeRangeSet {
+    /// build a range set from inclusive `(start, end)` scalar ranges,
+    /// coalescing any that overlap or are adjacent so `contains` can binary
+    /// search the result
+    pub fn new(ranges: impl IntoIterator<Item = (char, char)>) -> UnicodeRangeSet {
+        let mut pairs: Vec<(u32, u32)> = ranges.into_iter()
+            .map(|(start, end)| (start as u32, end as u32))
+            .collect();
+        pairs.sort_by_key(|&(start, _)| start);
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(pairs.len());
+        for (start, end) in pairs {
+            match merged.last_mut() {
+                Some(last) if start <= last.1.saturating_add(1) => {
+                    if end > last.1 { last.1 = end; }
+                }
+                _ => merged.push((start, end)),
+            }
+        }
+        UnicodeRangeSet { ranges: merged }
+    }
+
+    /// parse a compact, comma-separated list of inclusive hexadecimal
+    /// scalar ranges, e.g. `"0000-024F,0370-03FF,4E00-9FFF"`
+    pub fn parse(spec: &str) -> Result<UnicodeRangeSet, RangeSetParseError> {
+        let mut ranges = Vec::new();
+        for part in spec.split(',') {
+            let part = part.trim();
+            if part.is_empty() {
+                continue;
+            }
+            let (start, end) = part.split_once('-')
+                .ok_or_else(|| RangeSetParseError(format!("missing '-' in range '{}'", part)))?;
+            let start = u32::from_str_radix(start.trim(), 16)
+                .map_err(|_| RangeSetParseError(format!("invalid range start in '{}'", part)))?;
+            let end = u32::from_str_radix(end.trim(), 16)
+                .map_err(|_| RangeSetParseError(format!("invalid range end in '{}'", part)))?;
+            if start > end {
+                return Err(RangeSetParseError(format!("range '{}' starts after it ends", part)));
+            }
+            let start = char::from_u32(start)
+                .ok_or_else(|| RangeSetParseError(format!("'{:04X}' is not a scalar value", start)))?;
+            let end = char::from_u32(end)
+                .ok_or_else(|| RangeSetParseError(format!("'{:04X}' is not a scalar value", end)))?;
+            ranges.push((start, end));
+        }
+        Ok(UnicodeRangeSet::new(ranges))
+    }
+
+    /// whether `c` falls within one of this set's ranges
+    pub fn contains(&self, c: char) -> bool {
+        let cp = c as u32;
+        self.ranges.binary_search_by(|&(start, end)| {
+            if cp < start {
+                std::cmp::Ordering::Greater
+            } else if cp > end {
+                std::cmp::Ordering::Less
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        }).is_ok()
+    }
+}
+
 impl Text {
     /// create a new text from a string
     pub fn new (s: &str) -> Text {
-        let mut flag = 0;
+        Self::encode(s, 0)
+    }
+
+    /// create a new text from a string, first normalizing it to Unicode
+    /// Normalization Form C and recording that in the `NORMALIZED` flag, so
+    /// texts that only differ by Unicode composition (e.g. a precomposed
+    /// "é" vs. "e" + combining acute) encode identically and compare equal.
+    /// Only the precomposed letters listed in this module's decomposition
+    /// table and Hangul syllables are normalized; canonically equivalent
+    /// spellings of other characters are left as they are and still differ.
+    pub fn new_normalized (s: &str) -> Text {
+        Self::encode(&to_nfc(s), NORMALIZED)
+    }
+
+    fn encode (s: &str, extra_flags: u8) -> Text {
+        let mut flag = extra_flags;
         let mut utf16encoded = Vec::new();
         for utf16 in s.encode_utf16() {
             utf16encoded.write_u16::<LittleEndian>(utf16).unwrap();
@@ -93,8 +647,18 @@ impl Text {
         Text{encoded}
     }
 
-    pub fn from_encoded(encoded: &[u8]) -> Text {
-        Text{encoded: encoded.to_vec()}
+    /// rebuild a `Text` from its wire encoding, e.g. as received over `p2p_defiads`.
+    /// rejects an empty buffer and a flag byte using bits this version does not
+    /// understand, since either would otherwise surface as a panic or a hard
+    /// decode error much later, once the bytes are actually read.
+    pub fn from_encoded(encoded: &[u8]) -> Result<Text, TextError> {
+        if encoded.is_empty() {
+            return Err(TextError::Empty);
+        }
+        if encoded[0] & !KNOWN_FLAGS != 0 {
+            return Err(TextError::UnknownFlags(encoded[0]));
+        }
+        Ok(Text{encoded: encoded.to_vec()})
     }
 
     /// return encoded storage
@@ -102,35 +666,159 @@ impl Text {
         self.encoded.as_slice()
     }
 
-    /// decode the text into a regular string
-    pub fn as_string (&self) -> Result<String, Box<dyn Error>> {
-        let mut buffer;
-        let data = if self.encoded[0] & COMPRESSED != 0 {
+    // decompress (if needed) the payload following the flag byte
+    fn payload(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if self.encoded[0] & COMPRESSED != 0 {
             let mut decompressor = snap::Reader::new(io::Cursor::new(&self.encoded[1..]));
-            buffer = Vec::new();
+            let mut buffer = Vec::new();
             decompressor.read_to_end(&mut buffer)?;
-            buffer
+            Ok(buffer)
         }
         else {
-            self.encoded[1..].to_vec()
-        };
+            Ok(self.encoded[1..].to_vec())
+        }
+    }
+
+    // little-endian bytes to UTF-16 code units, dropping a dangling odd byte
+    fn utf16_units(data: &[u8]) -> Vec<u16> {
+        let mut units = Vec::new();
+        let mut cursor = Cursor::new(data);
+        while let Ok(unit) = cursor.read_u16::<LittleEndian>() {
+            units.push(unit);
+        }
+        units
+    }
+
+    /// decode the text into a regular string, failing on the first invalid
+    /// byte or code unit rather than guessing
+    pub fn as_string (&self) -> Result<String, Box<dyn Error>> {
+        let data = self.payload()?;
         if self.encoded[0] & UTF_16 != 0 {
-            let mut utf16points = Vec::new();
-            let mut cursor = Cursor::new(data);
-            while let Ok(utf16) = cursor.read_u16::<LittleEndian>() {
-                utf16points.push(utf16);
+            let units = Self::utf16_units(&data);
+            let s = decode_utf16_strict(&units).map_err(|position| Box::new(TextError::Invalid { position }) as Box<dyn Error>)?;
+            // a dangling odd byte is an incomplete code unit at offset units.len()
+            if data.len() % 2 != 0 {
+                return Err(Box::new(TextError::Invalid { position: units.len() }));
             }
-            Ok(String::from_utf16(utf16points.as_slice())?)
+            Ok(s)
         }
         else {
-            Ok(String::from_utf8(data)?)
+            decode_utf8_strict(&data).map_err(|position| Box::new(TextError::Invalid { position }) as Box<dyn Error>)
         }
     }
 
+    /// decode the text into a regular string, never failing: ill-formed
+    /// UTF-8 subsequences and lone UTF-16 surrogates are each replaced by
+    /// U+FFFD. The returned boolean is `true` if any replacement happened.
+    pub fn as_string_lossy (&self) -> (String, bool) {
+        let data = match self.payload() {
+            Ok(data) => data,
+            Err(_) => return ('\u{FFFD}'.to_string(), true),
+        };
+        if self.encoded[0] & UTF_16 != 0 {
+            let units = Self::utf16_units(&data);
+            let mut was_lossy = false;
+            let s: String = char::decode_utf16(units.iter().cloned())
+                .map(|unit| unit.unwrap_or_else(|_| { was_lossy = true; '\u{FFFD}' }))
+                .collect();
+            (s, was_lossy)
+        }
+        else {
+            decode_utf8_lossy(&data)
+        }
+    }
+
+    /// return a render-safe string where scalars that could be used to
+    /// spoof displayed text (bidi overrides, invisible characters, control
+    /// characters and noncharacters) are replaced by a visible escape holding
+    /// the scalar's lowercase hex value without padding, e.g. `\u{202e}` or
+    /// `\u{a}`. Ordinary printable text, including CJK/Arabic/Hebrew, is
+    /// left intact. Decoding falls back to `as_string_lossy` so malformed
+    /// input can never panic or fail to display at all.
+    pub fn as_display_string (&self) -> String {
+        let (s, _) = self.as_string_lossy();
+        let mut display = String::with_capacity(s.len());
+        for c in s.chars() {
+            if needs_display_escape(c) {
+                display.push_str(&format!("\\u{{{:x}}}", c as u32));
+            }
+            else {
+                display.push(c);
+            }
+        }
+        display
+    }
+
+    /// true if this text contains no bidi overrides, invisible characters,
+    /// control characters or noncharacters, i.e. `as_display_string` would
+    /// return it unchanged
+    pub fn is_display_safe (&self) -> bool {
+        let (s, _) = self.as_string_lossy();
+        !s.chars().any(needs_display_escape)
+    }
+
+    /// decode the text and return `(byte_offset, char)` pairs over the
+    /// resulting string, so callers can reason about character boundaries
+    /// without ever splitting a multi-byte scalar. Decoding is lossy, so
+    /// this never panics on malformed input.
+    pub fn char_indices (&self) -> Vec<(usize, char)> {
+        let (s, _) = self.as_string_lossy();
+        s.char_indices().collect()
+    }
+
+    /// trim this text to at most `max` encoded bytes, never splitting a
+    /// multi-byte scalar or orphaning a trailing combining mark of the
+    /// Combining Diacritical Marks block (U+0300-U+036F), and preserving NFC
+    /// normalization if this text was normalized. Marks of other blocks are
+    /// treated as starters and may be cut off from their base. The
+    /// result re-encodes from scratch, so its `as_bytes().len()` may differ
+    /// from a naive byte slice even when no characters were dropped.
+    /// Guaranteed to fit within `max` for any `max >= 1`.
+    pub fn truncate_to_bytes (&self, max: usize) -> Text {
+        let (s, _) = self.as_string_lossy();
+        let boundaries = cluster_boundaries(&s);
+        let mut smallest = Text::new("");
+        for &cut in boundaries.iter().rev() {
+            let candidate = if self.is_normalized() {
+                Text::new_normalized(&s[..cut])
+            } else {
+                Text::new(&s[..cut])
+            };
+            if cut == 0 {
+                smallest = candidate;
+                break;
+            }
+            if candidate.as_bytes().len() <= max {
+                return candidate;
+            }
+        }
+        smallest
+    }
+
+    /// decode the text and collect the scalars that fall outside `allowed`,
+    /// e.g. for a per-node content policy that restricts ads to a set of
+    /// scripts. Decoding is lossy, so this never panics on malformed input.
+    pub fn chars_outside (&self, allowed: &UnicodeRangeSet) -> Vec<char> {
+        let (s, _) = self.as_string_lossy();
+        s.chars().filter(|c| !allowed.contains(*c)).collect()
+    }
+
+    /// true if every scalar in this text falls within `allowed`
+    pub fn conforms_to (&self, allowed: &UnicodeRangeSet) -> bool {
+        let (s, _) = self.as_string_lossy();
+        s.chars().all(|c| allowed.contains(c))
+    }
+
     /// return the current encoding
     pub fn encoding (&self) -> u8 {
         return self.encoded[0]
     }
+
+    /// true if the producer recorded this text as already being in Unicode
+    /// Normalization Form C
+    pub fn is_normalized (&self) -> bool {
+        self.encoded[0] & NORMALIZED != 0
+    }
 }
 
 #[cfg(test)]
@@ -150,6 +838,243 @@ mod test {
     }
 
 
+    #[test]
+    fn test_from_encoded_rejects_malformed_input () {
+        assert!(Text::from_encoded(&[]).is_err());
+        assert!(Text::from_encoded(&[0xFF]).is_err());
+        assert!(Text::from_encoded(&[UTF_16 | COMPRESSED, 1, 2]).is_ok());
+    }
+
+    #[test]
+    fn test_deserialize_rejects_empty_encoded_without_panicking () {
+        // a Text arriving embedded in a larger p2p message is built by
+        // serde's generated Deserialize impl, not by a direct call to
+        // from_encoded; that path must reject the same malformed input
+        // instead of constructing a Text whose later accessors would
+        // panic indexing self.encoded[0]
+        let empty = serde_cbor::to_vec(&Vec::<u8>::new()).unwrap();
+        assert!(serde_cbor::from_slice::<Text>(&empty).is_err());
+
+        let valid = Text::new("round-trips fine");
+        let bytes = serde_cbor::to_vec(&valid).unwrap();
+        let round_tripped: Text = serde_cbor::from_slice(&bytes).unwrap();
+        assert_eq!(round_tripped, valid);
+    }
+
+    #[test]
+    fn test_deserialize_rejects_unknown_flags_before_is_normalized_can_see_it () {
+        // is_normalized (like encoding) indexes self.encoded[0] directly;
+        // it relies on the same from_encoded-on-every-deserialize
+        // invariant, not on any validation of its own, so a Text with an
+        // unknown flag byte must never survive deserialization to reach it
+        let unknown_flags = serde_cbor::to_vec(&vec![0xFFu8]).unwrap();
+        assert!(serde_cbor::from_slice::<Text>(&unknown_flags).is_err());
+
+        let valid = Text::new_normalized("round-trips fine");
+        let bytes = serde_cbor::to_vec(&valid).unwrap();
+        let round_tripped: Text = serde_cbor::from_slice(&bytes).unwrap();
+        assert!(round_tripped.is_normalized());
+    }
+
+    #[test]
+    fn test_as_string_lossy_never_panics_on_truncated_utf8 () {
+        // a lone 3-byte lead with no continuation bytes
+        let text = Text::from_encoded(&[0, 0xE0]).unwrap();
+        assert!(text.as_string().is_err());
+        let (s, was_lossy) = text.as_string_lossy();
+        assert_eq!(s, "\u{FFFD}");
+        assert!(was_lossy);
+    }
+
+    #[test]
+    fn test_as_string_lossy_rejects_overlong_and_surrogate_encodings () {
+        // overlong encoding of U+002F ('/') as 3 bytes: the second byte is
+        // out of range for an 0xE0 lead, so the lead byte alone is one bad
+        // subsequence, then the two stray continuation bytes are each
+        // rescanned individually, so
+        // lossy decode reports three replacement characters, matching
+        // `String::from_utf8_lossy`'s maximal-subpart resync behavior
+        let overlong = Text::from_encoded(&[0, 0xE0, 0x80, 0xAF]).unwrap();
+        assert!(overlong.as_string().is_err());
+        assert_eq!(overlong.as_string_lossy().0, "\u{FFFD}\u{FFFD}\u{FFFD}");
+
+        // 3-byte encoding of a UTF-16 surrogate half (U+D800); same
+        // one-bad-lead-plus-two-orphaned-continuation-bytes resync as above
+        let surrogate = Text::from_encoded(&[0, 0xED, 0xA0, 0x80]).unwrap();
+        assert!(surrogate.as_string().is_err());
+        assert_eq!(surrogate.as_string_lossy().0, "\u{FFFD}\u{FFFD}\u{FFFD}");
+    }
+
+    #[test]
+    fn test_invalid_position_points_at_the_offending_byte_not_the_scalar_start () {
+        // 'a' (1 valid byte) followed by a two-byte lead (0xC2) whose
+        // continuation byte is ASCII instead of a continuation byte; the
+        // reported position must be the continuation byte (index 2), not
+        // the lead byte that started the doomed scalar (index 1)
+        let text = Text::from_encoded(&[0, b'a', 0xC2, 0x41]).unwrap();
+        match *text.as_string().unwrap_err().downcast::<TextError>().unwrap() {
+            TextError::Invalid { position } => assert_eq!(position, 2),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_invalid_position_counts_utf16_code_units_not_chars () {
+        // a valid surrogate pair (2 code units, 1 char) followed by an
+        // unpaired high surrogate; the reported position must be the code
+        // unit offset (2), not the decoded char index (1)
+        let mut payload = Vec::new();
+        for unit in [0xD83Du16, 0xDE00, 0xD800] {
+            payload.extend_from_slice(&unit.to_le_bytes());
+        }
+        let mut encoded = vec![UTF_16];
+        encoded.extend_from_slice(&payload);
+        let text = Text::from_encoded(&encoded).unwrap();
+        match *text.as_string().unwrap_err().downcast::<TextError>().unwrap() {
+            TextError::Invalid { position } => assert_eq!(position, 2),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_new_normalized_dedupes_equivalent_compositions () {
+        let precomposed = Text::new_normalized("caf\u{e9}"); // "café", é as U+00E9
+        let decomposed = Text::new_normalized("cafe\u{301}"); // "e" + combining acute
+        assert!(precomposed.is_normalized());
+        assert_eq!(precomposed.as_string().unwrap(), decomposed.as_string().unwrap());
+        assert_eq!(precomposed.as_bytes(), decomposed.as_bytes());
+    }
+
+    #[test]
+    fn test_new_normalized_reorders_combining_marks_before_composing () {
+        // combining marks in non-canonical order around the same base letter
+        // still compose to the same NFC form regardless of input order
+        let a = Text::new_normalized("q\u{328}\u{301}"); // ogonek then acute
+        let b = Text::new_normalized("q\u{301}\u{328}"); // acute then ogonek
+        assert_eq!(a.as_string().unwrap(), b.as_string().unwrap());
+    }
+
+    #[test]
+    fn test_new_normalized_is_noop_for_scripts_without_decomposition () {
+        let (language, example) = EXAMPLES[4]; // Georgian
+        let text = Text::new_normalized(example);
+        assert_eq!(text.as_string().unwrap(), example.to_string(), "{}", language);
+    }
+
+    #[test]
+    fn test_new_normalized_dedupes_precomposed_cyrillic_and_greek () {
+        // й = и + combining breve (U+0306); precomposed vs. decomposed
+        // spellings of the same Russian word must normalize equal
+        let precomposed = Text::new_normalized("война"); // "war"
+        let decomposed = Text::new_normalized("вои\u{306}на");
+        assert_eq!(precomposed.as_string().unwrap(), decomposed.as_string().unwrap());
+        assert_eq!(precomposed.as_bytes(), decomposed.as_bytes());
+
+        // ё = е + combining diaeresis (U+0308)
+        let precomposed = Text::new_normalized("ёлка"); // "fir tree"
+        let decomposed = Text::new_normalized("е\u{308}лка");
+        assert_eq!(precomposed.as_string().unwrap(), decomposed.as_string().unwrap());
+        assert_eq!(precomposed.as_bytes(), decomposed.as_bytes());
+
+        // ά = α + combining acute/tonos (U+0301)
+        let precomposed = Text::new_normalized("άλφα");
+        let decomposed = Text::new_normalized("α\u{301}λφα");
+        assert_eq!(precomposed.as_string().unwrap(), decomposed.as_string().unwrap());
+        assert_eq!(precomposed.as_bytes(), decomposed.as_bytes());
+    }
+
+    #[test]
+    fn test_new_normalized_composes_hangul_jamo () {
+        let precomposed = Text::new_normalized("\u{AC00}"); // 가
+        let from_jamo = Text::new_normalized("\u{1100}\u{1161}"); // L + V
+        assert_eq!(precomposed.as_string().unwrap(), from_jamo.as_string().unwrap());
+        assert_eq!(precomposed.as_string().unwrap(), "\u{AC00}");
+    }
+
+    #[test]
+    fn test_as_display_string_escapes_bidi_override () {
+        let text = Text::new("pay \u{202E}100$ 1\u{202C}");
+        assert!(!text.is_display_safe());
+        assert_eq!(text.as_display_string(), "pay \\u{202e}100$ 1\\u{202c}");
+    }
+
+    #[test]
+    fn test_as_display_string_escapes_invisible_characters () {
+        let text = Text::new("go\u{200b}ogle.com");
+        assert!(!text.is_display_safe());
+        assert_eq!(text.as_display_string(), "go\\u{200b}ogle.com");
+    }
+
+    #[test]
+    fn test_as_display_string_leaves_ordinary_scripts_intact () {
+        // newlines are C0 controls and are intentionally escaped, so this
+        // exercises one paragraph of each script rather than the full
+        // (newline-separated) EXAMPLES corpus
+        for (language, example) in &EXAMPLES[..] {
+            let paragraph = example.lines().next().unwrap();
+            let text = Text::new(paragraph);
+            assert!(text.is_display_safe(), "{}", language);
+            assert_eq!(&text.as_display_string(), paragraph);
+        }
+    }
+
+    #[test]
+    fn test_char_indices_matches_str_char_indices () {
+        let s = "a\u{301}b\u{301}c"; // decomposed é-like clusters
+        let text = Text::new(s);
+        let expected: Vec<(usize, char)> = s.char_indices().collect();
+        assert_eq!(text.char_indices(), expected);
+    }
+
+    #[test]
+    fn test_truncate_to_bytes_never_splits_a_combining_sequence () {
+        // "e" + combining acute must truncate as a whole cluster or not at all
+        let text = Text::new("e\u{301}f");
+        for max in 1..=text.as_bytes().len() {
+            let truncated = text.truncate_to_bytes(max);
+            assert!(truncated.as_bytes().len() <= max);
+            let (s, _) = truncated.as_string_lossy();
+            assert!(s.is_empty() || s == "e\u{301}" || s == "e\u{301}f");
+        }
+    }
+
+    #[test]
+    fn test_truncate_to_bytes_preserves_normalization_flag () {
+        let text = Text::new_normalized("caf\u{e9} shop");
+        let truncated = text.truncate_to_bytes(text.as_bytes().len() - 1);
+        assert!(truncated.is_normalized());
+    }
+
+    #[test]
+    fn test_range_set_parse_merges_overlapping_and_adjacent_ranges () {
+        let set = UnicodeRangeSet::parse("0041-0043,0044-0046,0050-0060").unwrap();
+        assert!(set.contains('A'));
+        assert!(set.contains('F')); // 0041-0046 merged from two adjacent ranges
+        assert!(!set.contains('G'));
+        assert!(set.contains('\u{50}'));
+    }
+
+    #[test]
+    fn test_range_set_parse_rejects_malformed_spec () {
+        assert!(UnicodeRangeSet::parse("0041").is_err());
+        assert!(UnicodeRangeSet::parse("ZZZZ-0046").is_err());
+        assert!(UnicodeRangeSet::parse("0046-0041").is_err());
+    }
+
+    #[test]
+    fn test_conforms_to_and_chars_outside () {
+        // Basic Latin only
+        let latin = UnicodeRangeSet::parse("0000-007F").unwrap();
+        let ascii = Text::new("hello");
+        assert!(ascii.conforms_to(&latin));
+        assert!(ascii.chars_outside(&latin).is_empty());
+
+        let (language, example) = EXAMPLES[6]; // Chinese
+        let cjk = Text::new(example.lines().next().unwrap());
+        assert!(!cjk.conforms_to(&latin), "{}", language);
+        assert!(!cjk.chars_outside(&latin).is_empty());
+    }
+
     const EXAMPLES: [(&'static str, &'static str); 14] = [
         ("Latin", "Lorem ipsum dolor sit amet, ius te animal perpetua efficiantur, porro dolorem ea mel. Cu duo malorum fastidii delicatissimi, pro dico everti argumentum ex. Ea qui liber solet. Ignota sanctus saperet sea ut, vidisse fuisset eos an. Ius an appareat mediocritatem, eu amet noster reprimique his. Eos in elitr integre mentitum, his fabulas salutatus ea.
 